@@ -0,0 +1,29 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use trice::Instant;
+
+/// A 'static, thread-safe view into the cancellation status of a [`super::Context`] and all of
+/// its ancestors, for use by embedded scripting which cannot hold a borrowed reference back into
+/// the context.
+#[derive(Clone)]
+pub struct Cancellation {
+	deadline: Option<Instant>,
+	cancelled: Vec<Arc<AtomicBool>>,
+}
+
+impl Cancellation {
+	pub fn new(deadline: Option<Instant>, cancelled: Vec<Arc<AtomicBool>>) -> Self {
+		Self {
+			deadline,
+			cancelled,
+		}
+	}
+
+	/// Check if the context has been cancelled, or its deadline has passed.
+	pub fn is_done(&self) -> bool {
+		match self.deadline {
+			Some(deadline) if deadline <= Instant::now() => true,
+			_ => self.cancelled.iter().any(|v| v.load(Ordering::Relaxed)),
+		}
+	}
+}
@@ -0,0 +1,35 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// A cancellation handle returned from [`super::Context::add_cancel`].
+///
+/// Calling [`Canceller::cancel`] or [`Canceller::cancel_with`] marks the associated context, and
+/// all of its children, as cancelled, and wakes any task awaiting [`super::Context::cancelled`].
+pub struct Canceller(Arc<AtomicBool>, Arc<Mutex<Option<Cow<'static, str>>>>, Arc<Notify>);
+
+impl Canceller {
+	pub(crate) fn new(
+		cancelled: Arc<AtomicBool>,
+		cause: Arc<Mutex<Option<Cow<'static, str>>>>,
+		notify: Arc<Notify>,
+	) -> Canceller {
+		Canceller(cancelled, cause, notify)
+	}
+
+	/// Cancel the associated context.
+	pub fn cancel(self) {
+		self.0.store(true, Ordering::Relaxed);
+		self.2.notify_waiters();
+	}
+
+	/// Cancel the associated context, recording `cause` as the reason it was cancelled. The
+	/// cause is surfaced by `Context::done` as `Reason::Canceled { cause }`, e.g. "client
+	/// disconnected" or "superseded by newer live query".
+	pub fn cancel_with(self, cause: Cow<'static, str>) {
+		*self.1.lock().unwrap() = Some(cause);
+		self.0.store(true, Ordering::Relaxed);
+		self.2.notify_waiters();
+	}
+}
@@ -5,12 +5,19 @@ use crate::err::Error;
 use crate::idx::planner::executor::QueryExecutor;
 use crate::sql::value::Value;
 use crate::sql::Thing;
+use std::any::{Any, TypeId};
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::{self, Debug};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
 use std::time::Duration;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use tokio::time::Sleep;
 use trice::Instant;
 
 impl<'a> From<Value> for Cow<'a, Value> {
@@ -27,12 +34,23 @@ impl<'a> From<&'a Value> for Cow<'a, Value> {
 pub struct Context<'a> {
 	// An optional parent context.
 	parent: Option<&'a Context<'a>>,
+	// Additional parent contexts, used when this context fans-in multiple cancellation sources
+	// (see `Context::combine`). Checked in order, after `parent`.
+	parents: Vec<&'a Context<'a>>,
 	// An optional deadline.
 	deadline: Option<Instant>,
 	// Whether or not this context is cancelled.
 	cancelled: Arc<AtomicBool>,
+	// The cause given, if any, when this context was cancelled via `Canceller::cancel_with`.
+	cancel_cause: Arc<Mutex<Option<Cow<'static, str>>>>,
+	// Notified by `Canceller` when this context is cancelled, so that `Context::cancelled` can
+	// wake without polling `done()`.
+	notify: Arc<Notify>,
 	// A collection of read only values stored in this context.
 	values: HashMap<Cow<'static, str>, Cow<'a, Value>>,
+	// A collection of arbitrary, type-erased Rust values stored in this context, keyed by their
+	// `TypeId`, for internal state that doesn't need to be encoded as a `sql::Value`.
+	typed_values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
 	// An optional transaction
 	transaction: Option<Transaction>,
 	// An optional query executor
@@ -53,8 +71,10 @@ impl<'a> Debug for Context<'a> {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		f.debug_struct("Context")
 			.field("parent", &self.parent)
+			.field("parents", &self.parents)
 			.field("deadline", &self.deadline)
 			.field("cancelled", &self.cancelled)
+			.field("cancel_cause", &self.cancel_cause)
 			.field("values", &self.values)
 			.field("thing", &self.thing)
 			.field("doc", &self.cursor_doc)
@@ -67,9 +87,13 @@ impl<'a> Context<'a> {
 	pub fn background() -> Self {
 		Context {
 			values: HashMap::default(),
+			typed_values: HashMap::default(),
 			parent: None,
+			parents: Vec::new(),
 			deadline: None,
 			cancelled: Arc::new(AtomicBool::new(false)),
+			cancel_cause: Arc::new(Mutex::new(None)),
+			notify: Arc::new(Notify::new()),
 			transaction: None,
 			query_executors: None,
 			thing: None,
@@ -81,9 +105,13 @@ impl<'a> Context<'a> {
 	pub fn new(parent: &'a Context) -> Self {
 		Context {
 			values: HashMap::default(),
+			typed_values: HashMap::default(),
 			parent: Some(parent),
+			parents: Vec::new(),
 			deadline: parent.deadline,
 			cancelled: Arc::new(AtomicBool::new(false)),
+			cancel_cause: Arc::new(Mutex::new(None)),
+			notify: Arc::new(Notify::new()),
 			transaction: parent.transaction.clone(),
 			query_executors: parent.query_executors.clone(),
 			thing: parent.thing,
@@ -91,11 +119,42 @@ impl<'a> Context<'a> {
 		}
 	}
 
+	/// Create a new context that is bounded by several independent parent contexts at once,
+	/// mirroring a `onecontext`-style merge. The combined context is done as soon as any one of
+	/// `parents` is done, its effective deadline is the earliest deadline among `parents`, and
+	/// `value` searches `parents` in order.
+	pub fn combine(parents: &[&'a Context<'a>]) -> Self {
+		let deadline = parents.iter().filter_map(|p| p.deadline).min();
+		// Inherit the transaction, executors, record id and cursor document from the first
+		// parent that has one, same precedence as `value`/`done`. This mirrors `new()`, which
+		// inherits all of these from its single parent.
+		let transaction = parents.iter().find_map(|p| p.transaction.clone());
+		let query_executors = parents.iter().find_map(|p| p.query_executors.clone());
+		let thing = parents.iter().find_map(|p| p.thing);
+		let cursor_doc = parents.iter().find_map(|p| p.cursor_doc);
+		Context {
+			values: HashMap::default(),
+			typed_values: HashMap::default(),
+			parent: None,
+			notify: Arc::new(Notify::new()),
+			parents: parents.to_vec(),
+			deadline,
+			cancelled: Arc::new(AtomicBool::new(false)),
+			cancel_cause: Arc::new(Mutex::new(None)),
+			transaction,
+			query_executors,
+			thing,
+			cursor_doc,
+		}
+	}
+
 	/// Add cancellation to the context. The value that is returned will cancel
 	/// the context and it's children once called.
 	pub fn add_cancel(&mut self) -> Canceller {
 		let cancelled = self.cancelled.clone();
-		Canceller::new(cancelled)
+		let cause = self.cancel_cause.clone();
+		let notify = self.notify.clone();
+		Canceller::new(cancelled, cause, notify)
 	}
 
 	/// Add a deadline to the context. If the current deadline is sooner than
@@ -146,6 +205,14 @@ impl<'a> Context<'a> {
 		self.values.insert(key.into(), value.into());
 	}
 
+	/// Add an arbitrary, type-erased Rust value to the context. It overwrites any previously set
+	/// value of the same type. Unlike `add_value`, this does not require encoding the value as a
+	/// `sql::Value`, so it is suited to internal state such as a planner cache, a per-request
+	/// metrics accumulator, or an auth principal struct.
+	pub fn add_typed<T: Any + Send + Sync>(&mut self, val: T) {
+		self.typed_values.insert(TypeId::of::<T>(), Arc::new(val));
+	}
+
 	/// Get the timeout for this operation, if any. This is useful for
 	/// checking if a long job should be started or not.
 	pub fn timeout(&self) -> Option<Duration> {
@@ -180,11 +247,25 @@ impl<'a> Context<'a> {
 	pub fn done(&self) -> Option<Reason> {
 		match self.deadline {
 			Some(deadline) if deadline <= Instant::now() => Some(Reason::Timedout),
-			_ if self.cancelled.load(Ordering::Relaxed) => Some(Reason::Canceled),
-			_ => match self.parent {
-				Some(ctx) => ctx.done(),
-				_ => None,
-			},
+			_ if self.cancelled.load(Ordering::Relaxed) => {
+				let cause = self.cancel_cause.lock().unwrap().clone().map(Cow::into_owned);
+				Some(Reason::Canceled {
+					cause,
+				})
+			}
+			_ => {
+				if let Some(ctx) = self.parent {
+					if let Some(reason) = ctx.done() {
+						return Some(reason);
+					}
+				}
+				for ctx in &self.parents {
+					if let Some(reason) = ctx.done() {
+						return Some(reason);
+					}
+				}
+				None
+			}
 		}
 	}
 
@@ -211,21 +292,269 @@ impl<'a> Context<'a> {
 				Cow::Borrowed(v) => Some(*v),
 				Cow::Owned(v) => Some(v),
 			},
-			None => match self.parent {
-				Some(p) => p.value(key),
-				_ => None,
-			},
+			None => {
+				if let Some(p) = self.parent {
+					if let Some(v) = p.value(key) {
+						return Some(v);
+					}
+				}
+				for p in &self.parents {
+					if let Some(v) = p.value(key) {
+						return Some(v);
+					}
+				}
+				None
+			}
+		}
+	}
+
+	/// Get an arbitrary, type-erased Rust value from the context. If no value is stored under
+	/// the given type, then this will return None. Falls back to the parent chain, in the same
+	/// order as `value`.
+	pub fn get_typed<T: Any>(&self) -> Option<&T> {
+		match self.typed_values.get(&TypeId::of::<T>()) {
+			Some(v) => v.downcast_ref::<T>(),
+			None => {
+				if let Some(p) = self.parent {
+					if let Some(v) = p.get_typed::<T>() {
+						return Some(v);
+					}
+				}
+				for p in &self.parents {
+					if let Some(v) = p.get_typed::<T>() {
+						return Some(v);
+					}
+				}
+				None
+			}
+		}
+	}
+
+	/// Get a future that resolves once this context (or one of its ancestors) is cancelled, or
+	/// its deadline elapses. This lets an executor `tokio::select!` between doing real work and
+	/// reacting to cancellation, instead of busy-polling `done()` in a loop.
+	pub fn cancelled(&self) -> Cancelled<'_, 'a> {
+		Cancelled {
+			ctx: self,
+			notified: None,
+			sleep: None,
+			parents: Vec::new(),
 		}
 	}
 
 	/// Get a 'static view into the cancellation status.
 	#[cfg(feature = "scripting")]
 	pub fn cancellation(&self) -> crate::ctx::cancellation::Cancellation {
-		crate::ctx::cancellation::Cancellation::new(
-			self.deadline,
-			std::iter::successors(Some(self), |ctx| ctx.parent)
-				.map(|ctx| ctx.cancelled.clone())
-				.collect(),
-		)
+		let mut cancelled = Vec::new();
+		self.collect_cancelled(&mut cancelled);
+		crate::ctx::cancellation::Cancellation::new(self.deadline, cancelled)
+	}
+
+	/// Collect the cancellation flags of this context and all of its ancestors.
+	#[cfg(feature = "scripting")]
+	fn collect_cancelled(&self, out: &mut Vec<Arc<AtomicBool>>) {
+		out.push(self.cancelled.clone());
+		if let Some(ctx) = self.parent {
+			ctx.collect_cancelled(out);
+		}
+		for ctx in &self.parents {
+			ctx.collect_cancelled(out);
+		}
+	}
+}
+
+/// The future returned by [`Context::cancelled`]. Resolves with the [`Reason`] the context was
+/// done for, waking as soon as `Canceller` notifies the context, its deadline elapses, or any
+/// ancestor is done.
+///
+/// `'ctx` is the lifetime of the borrow of the context being awaited; `'a` is `Context`'s own
+/// lifetime parameter. Keeping them separate lets `cancelled` be called on a `&Context<'a>`
+/// borrowed for any lifetime, however short.
+pub struct Cancelled<'ctx, 'a> {
+	ctx: &'ctx Context<'a>,
+	notified: Option<Pin<Box<Notified<'ctx>>>>,
+	sleep: Option<Pin<Box<Sleep>>>,
+	parents: Vec<Pin<Box<Cancelled<'ctx, 'a>>>>,
+}
+
+impl<'ctx, 'a> Future for Cancelled<'ctx, 'a> {
+	type Output = Reason;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Reason> {
+		// `Notify::notify_waiters` wakes only waiters that are already registered at the time it
+		// is called - unlike `notify_one`, it stores no permit for a waiter that registers
+		// afterwards. So the `Notified` future must be armed and polled (which registers us as a
+		// waiter) *before* we consult `done()`, otherwise a `Canceller::cancel` that runs between
+		// a `done()` check and the registration below would wake nobody and this future would
+		// hang forever. `done()` is therefore checked last, once every waiter for this poll has
+		// been registered.
+		if self.sleep.is_none() {
+			if let Some(deadline) = self.ctx.deadline {
+				let remaining = deadline.saturating_duration_since(Instant::now());
+				self.sleep = Some(Box::pin(tokio::time::sleep(remaining)));
+			}
+		}
+		if let Some(sleep) = self.sleep.as_mut() {
+			if sleep.as_mut().poll(cx).is_ready() {
+				return Poll::Ready(Reason::Timedout);
+			}
+		}
+
+		if self.notified.is_none() {
+			self.notified = Some(Box::pin(self.ctx.notify.notified()));
+		}
+		if let Some(notified) = self.notified.as_mut() {
+			if notified.as_mut().poll(cx).is_ready() {
+				// Re-arm so a later notification on this context is not missed.
+				self.notified = None;
+			}
+		}
+
+		if self.parents.is_empty() {
+			if let Some(p) = self.ctx.parent {
+				self.parents.push(Box::pin(p.cancelled()));
+			}
+			for p in &self.ctx.parents {
+				self.parents.push(Box::pin(p.cancelled()));
+			}
+		}
+		for fut in self.parents.iter_mut() {
+			if let Poll::Ready(reason) = fut.as_mut().poll(cx) {
+				return Poll::Ready(reason);
+			}
+		}
+
+		// Every waiter for this poll (our own `notify`, the timer, and each parent's) is now
+		// registered, so it is safe to check `done()` without risking a lost wakeup.
+		if let Some(reason) = self.ctx.done() {
+			return Poll::Ready(reason);
+		}
+
+		Poll::Pending
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn cancelled_resolves_on_cancel() {
+		// `cancelled()` must be callable on an ordinary, locally owned `&Context`.
+		let mut ctx = Context::background();
+		let canceller = ctx.add_cancel();
+		tokio::spawn(async move {
+			canceller.cancel();
+		});
+		let reason = ctx.cancelled().await;
+		assert!(reason.is_cancelled());
+	}
+
+	#[tokio::test]
+	async fn cancelled_resolves_on_ancestor_cancel() {
+		let mut parent = Context::background();
+		let canceller = parent.add_cancel();
+		let child = Context::new(&parent);
+		tokio::spawn(async move {
+			canceller.cancel();
+		});
+		let reason = child.cancelled().await;
+		assert!(reason.is_cancelled());
+	}
+
+	// A current-thread runtime can't actually race `cancel()` against the registration inside
+	// `Cancelled::poll` - the spawned task never gets to run until the awaiting task yields -
+	// so the single-threaded tests above can't exercise the lost-wakeup window between `done()`
+	// and arming `Notify::notified()`. Run many iterations on a multi-thread runtime instead, so
+	// the cancel can genuinely land concurrently with the first poll.
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn cancelled_resolves_on_concurrent_cancel() {
+		for _ in 0..200 {
+			let mut ctx = Context::background();
+			let canceller = ctx.add_cancel();
+			let handle = tokio::spawn(async move {
+				canceller.cancel();
+			});
+			let reason = ctx.cancelled().await;
+			assert!(reason.is_cancelled());
+			handle.await.unwrap();
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn cancelled_resolves_on_concurrent_ancestor_cancel() {
+		for _ in 0..200 {
+			let mut parent = Context::background();
+			let canceller = parent.add_cancel();
+			let child = Context::new(&parent);
+			let handle = tokio::spawn(async move {
+				canceller.cancel();
+			});
+			let reason = child.cancelled().await;
+			assert!(reason.is_cancelled());
+			handle.await.unwrap();
+		}
+	}
+
+	#[test]
+	fn combine_is_done_when_first_parent_fires() {
+		let mut a = Context::background();
+		let mut b = Context::background();
+		let b_cancel = b.add_cancel();
+		let combined = Context::combine(&[&a, &b]);
+		assert!(combined.done().is_none());
+		b_cancel.cancel();
+		assert!(combined.is_done());
+		// `a` never fires; its canceller is taken only to give the `mut` binding a use, and is
+		// dropped unused.
+		let _ = a.add_cancel();
+	}
+
+	#[test]
+	fn combine_deadline_is_earliest_of_parents() {
+		let mut near = Context::background();
+		near.add_timeout(Duration::from_secs(0));
+		let mut far = Context::background();
+		far.add_timeout(Duration::from_secs(3600));
+		let combined = Context::combine(&[&far, &near]);
+		assert!(combined.is_timedout());
+	}
+
+	#[test]
+	fn combine_value_searches_parents_in_order() {
+		let mut a = Context::background();
+		a.add_value("key", Value::from("a"));
+		let mut b = Context::background();
+		b.add_value("key", Value::from("b"));
+		let combined = Context::combine(&[&a, &b]);
+		assert_eq!(combined.value("key"), Some(&Value::from("a")));
+	}
+
+	#[test]
+	fn cancel_with_cause_is_visible_from_child() {
+		let mut parent = Context::background();
+		let canceller = parent.add_cancel();
+		canceller.cancel_with(Cow::Borrowed("killed by KILL statement"));
+		let child = Context::new(&parent);
+		let reason = child.done().expect("child should be done");
+		assert_eq!(reason.cause(), Some("killed by KILL statement"));
+	}
+
+	#[test]
+	fn typed_value_round_trips_and_falls_back_to_parent() {
+		#[derive(Debug, PartialEq)]
+		struct Principal(&'static str);
+
+		let mut parent = Context::background();
+		parent.add_typed(Principal("root"));
+
+		let mut child = Context::new(&parent);
+		assert_eq!(child.get_typed::<Principal>(), Some(&Principal("root")));
+
+		child.add_typed(Principal("guest"));
+		assert_eq!(child.get_typed::<Principal>(), Some(&Principal("guest")));
+		assert_eq!(parent.get_typed::<Principal>(), Some(&Principal("root")));
+
+		assert_eq!(child.get_typed::<u32>(), None);
 	}
 }
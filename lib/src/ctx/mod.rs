@@ -0,0 +1,13 @@
+mod canceller;
+mod context;
+mod reason;
+
+#[cfg(feature = "scripting")]
+mod cancellation;
+
+pub use canceller::Canceller;
+pub use context::{Cancelled, Context};
+pub use reason::Reason;
+
+#[cfg(feature = "scripting")]
+pub use cancellation::Cancellation;
@@ -0,0 +1,31 @@
+/// The reason why a [`super::Context`] is done.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Reason {
+	Timedout,
+	Canceled {
+		/// The cause given to the [`super::Canceller`] that cancelled the context, if any.
+		cause: Option<String>,
+	},
+}
+
+impl Reason {
+	/// Check if the context is done because of a timeout.
+	pub fn is_timedout(&self) -> bool {
+		matches!(self, Reason::Timedout)
+	}
+
+	/// Check if the context is done because it was cancelled.
+	pub fn is_cancelled(&self) -> bool {
+		matches!(self, Reason::Canceled { .. })
+	}
+
+	/// The cause given when the context was cancelled, if any.
+	pub fn cause(&self) -> Option<&str> {
+		match self {
+			Reason::Canceled {
+				cause,
+			} => cause.as_deref(),
+			Reason::Timedout => None,
+		}
+	}
+}